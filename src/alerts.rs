@@ -0,0 +1,120 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tracing::warn;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, poise::ChoiceParameter)]
+pub enum Direction {
+    #[name = "above"]
+    Above,
+    #[name = "below"]
+    Below,
+}
+
+/// A user's request to be DMed once `symbol` crosses `price` USD, stored in `ALERTS_FILE`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PriceAlert {
+    pub id: u64,
+    pub user_id: u64,
+    pub symbol: String,
+    pub direction: Direction,
+    pub price: f64,
+}
+
+impl PriceAlert {
+    pub fn is_triggered(&self, price: f64) -> bool {
+        match self.direction {
+            Direction::Above => price >= self.price,
+            Direction::Below => price <= self.price,
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        let direction = match self.direction {
+            Direction::Above => "above",
+            Direction::Below => "below",
+        };
+        format!("#{} {} {} ${}", self.id, self.symbol, direction, self.price)
+    }
+
+    pub fn load_all(path: &str) -> Vec<PriceAlert> {
+        match std::fs::read_to_string(path) {
+            Ok(json) => serde_json::from_str(&json).unwrap_or_else(|e| {
+                warn!("Ignoring malformed {}: {}", path, e);
+                Vec::new()
+            }),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    pub fn save_all(path: &str, alerts: &[PriceAlert]) -> std::io::Result<()> {
+        std::fs::write(path, serde_json::to_string_pretty(alerts)?)
+    }
+}
+
+/// The distinct symbols `alerts` watch, for a single batched quote request.
+pub fn watched_symbols(alerts: &[PriceAlert]) -> Vec<String> {
+    let mut symbols: Vec<String> = alerts.iter().map(|a| a.symbol.clone()).collect();
+    symbols.sort();
+    symbols.dedup();
+    symbols
+}
+
+/// Remove and return the alerts whose threshold `prices` (USD, keyed by symbol) crossed.
+pub fn take_triggered(
+    alerts: &mut Vec<PriceAlert>,
+    prices: &HashMap<String, f64>,
+) -> Vec<PriceAlert> {
+    let mut triggered = Vec::new();
+    alerts.retain(|alert| match prices.get(&alert.symbol) {
+        Some(price) if alert.is_triggered(*price) => {
+            triggered.push(alert.clone());
+            false
+        }
+        _ => true,
+    });
+    triggered
+}
+
+/// An id one higher than any alert in `alerts`.
+pub fn next_id(alerts: &[PriceAlert]) -> u64 {
+    alerts.iter().map(|a| a.id).max().unwrap_or(0) + 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn alert(id: u64, symbol: &str, direction: Direction, price: f64) -> PriceAlert {
+        PriceAlert {
+            id,
+            user_id: 42,
+            symbol: symbol.to_string(),
+            direction,
+            price,
+        }
+    }
+
+    #[test]
+    fn take_triggered_removes_crossed_alerts_only() {
+        let mut alerts = vec![
+            alert(1, "BTC", Direction::Above, 50000.0),
+            alert(2, "BTC", Direction::Below, 40000.0),
+            alert(3, "ETH", Direction::Below, 3000.0),
+            alert(4, "SOL", Direction::Above, 1.0),
+        ];
+        let prices = HashMap::from([("BTC".to_string(), 52000.0), ("ETH".to_string(), 2900.0)]);
+
+        let triggered = take_triggered(&mut alerts, &prices);
+
+        assert_eq!(
+            triggered.iter().map(|a| a.id).collect::<Vec<u64>>(),
+            vec![1, 3]
+        );
+        assert_eq!(
+            alerts.iter().map(|a| a.id).collect::<Vec<u64>>(),
+            vec![2, 4]
+        );
+        assert_eq!(watched_symbols(&alerts), vec!["BTC", "SOL"]);
+        assert_eq!(next_id(&alerts), 5);
+    }
+}
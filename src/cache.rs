@@ -0,0 +1,113 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    time::{Duration, Instant},
+};
+
+/// Recent replies keyed by `cache_key`, dropping the least recently used one past `capacity`.
+#[derive(Debug, Default)]
+pub struct ResponseCache {
+    capacity: usize,
+    entries: HashMap<u64, Entry>,
+    /// Bumped on every access to order entries by use
+    clock: u64,
+}
+
+#[derive(Debug)]
+struct Entry {
+    text: String,
+    stored: Instant,
+    last_used: u64,
+}
+
+impl ResponseCache {
+    pub fn new(capacity: usize) -> Self {
+        ResponseCache {
+            capacity,
+            ..Default::default()
+        }
+    }
+
+    /// The reply stored under `key` if it is younger than `ttl`.
+    pub fn get(&mut self, key: u64, ttl: Duration) -> Option<String> {
+        self.clock += 1;
+        let entry = self.entries.get_mut(&key)?;
+        if entry.stored.elapsed() >= ttl {
+            self.entries.remove(&key);
+            return None;
+        }
+        entry.last_used = self.clock;
+        Some(entry.text.clone())
+    }
+
+    pub fn insert(&mut self, key: u64, text: String) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.clock += 1;
+        if !self.entries.contains_key(&key) && self.entries.len() >= self.capacity {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| *key);
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.entries.insert(
+            key,
+            Entry {
+                text,
+                stored: Instant::now(),
+                last_used: self.clock,
+            },
+        );
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+/// Key for asking `engine` `question` after `context`, ignoring the question's case and spacing.
+pub fn cache_key(engine: &str, question: &str, context: &str) -> u64 {
+    let question = question
+        .split_whitespace()
+        .collect::<Vec<&str>>()
+        .join(" ")
+        .to_lowercase();
+    let mut hasher = DefaultHasher::new();
+    (engine, question, context).hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evicts_the_least_recently_used_reply() {
+        let ttl = Duration::from_secs(60);
+        let mut cache = ResponseCache::new(2);
+        cache.insert(1, "one".to_string());
+        cache.insert(2, "two".to_string());
+        assert_eq!(cache.get(1, ttl).as_deref(), Some("one"));
+
+        cache.insert(3, "three".to_string());
+
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(2, ttl), None);
+        assert_eq!(cache.get(1, ttl).as_deref(), Some("one"));
+        assert_eq!(cache.get(3, Duration::ZERO), None);
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn cache_key_ignores_case_and_spacing_only() {
+        let key = cache_key("gpt-4", "What is  BTC?", "[]");
+        assert_eq!(key, cache_key("gpt-4", " what is btc? ", "[]"));
+        assert_ne!(key, cache_key("gpt-3.5-turbo", "what is btc?", "[]"));
+        assert_ne!(key, cache_key("gpt-4", "what is btc?", "[\"context\"]"));
+    }
+}
@@ -0,0 +1,752 @@
+use async_openai::types::ImageSize;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    env, fmt,
+    ops::RangeInclusive,
+    str::FromStr,
+    time::Duration,
+};
+use tracing::warn;
+
+use crate::util::ChangeColors;
+
+pub const GPT_PROVIDER: &str = "gpt";
+pub const MISTRAL_PROVIDER: &str = "mistral";
+pub const TOP_P_RANGE: RangeInclusive<f32> = 0.0..=1.0;
+pub const PENALTY_RANGE: RangeInclusive<f32> = -2.0..=2.0;
+
+const DEFAULT_GPT_MODELS: &[&str] = &[
+    "gpt-3.5-turbo",
+    "gpt-3.5-turbo-16k",
+    "gpt-4",
+    "gpt-4-32k",
+    "gpt-4-1106-preview",
+    "gpt-4-0125-preview",
+];
+
+/// Default USD prices per 1k prompt and completion tokens, overridable with `MODEL_PRICES`.
+const DEFAULT_MODEL_PRICES: &[(&str, f64, f64)] = &[
+    ("gpt-3.5-turbo", 0.0005, 0.0015),
+    ("gpt-4", 0.03, 0.06),
+    ("gpt-4-32k", 0.06, 0.12),
+    ("gpt-4-1106-preview", 0.01, 0.03),
+    ("gpt-4-0125-preview", 0.01, 0.03),
+    ("gpt-4-turbo", 0.01, 0.03),
+    ("gpt-4o", 0.005, 0.015),
+    ("mistral-tiny", 0.00025, 0.00025),
+    ("mistral-small", 0.002, 0.006),
+    ("mistral-medium", 0.0027, 0.0081),
+];
+
+/// USD per 1k tokens.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ModelPrice {
+    pub prompt: f64,
+    pub completion: f64,
+}
+
+/// Most stop sequences OpenAI accepts in one request.
+pub const MAX_STOP_SEQUENCES: usize = 4;
+
+/// Sampling parameters, `None` leaving it to the API's default.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Sampling {
+    pub top_p: Option<f32>,
+    pub frequency_penalty: Option<f32>,
+    pub presence_penalty: Option<f32>,
+}
+
+impl Sampling {
+    /// These parameters, falling back to `defaults` for the ones not set.
+    pub fn or(self, defaults: Sampling) -> Sampling {
+        Sampling {
+            top_p: self.top_p.or(defaults.top_p),
+            frequency_penalty: self.frequency_penalty.or(defaults.frequency_penalty),
+            presence_penalty: self.presence_penalty.or(defaults.presence_penalty),
+        }
+    }
+}
+
+/// How many gateway shards to run, from `SHARD_COUNT`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Sharding {
+    /// One shard, fine below Discord's 2500 guilds per shard
+    #[default]
+    Single,
+    /// As many as Discord recommends for the bot's guild count
+    Auto,
+    Fixed(u32),
+}
+
+impl Sharding {
+    /// Parse `SHARD_COUNT`: empty for a single shard, `auto`, or a count of at least 1.
+    pub fn parse(raw: &str) -> Result<Sharding, String> {
+        match raw.trim() {
+            "" => Ok(Sharding::Single),
+            auto if auto.eq_ignore_ascii_case("auto") => Ok(Sharding::Auto),
+            count => match count.parse() {
+                Ok(count) if count > 0 => Ok(Sharding::Fixed(count)),
+                _ => Err(format!(
+                    "SHARD_COUNT must be auto or a number above 0, got {:?}",
+                    count
+                )),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RetryConfig {
+    pub max_retries: u32,
+    pub base_delay_ms: u64,
+}
+
+/// Everything the bot reads from the environment, validated once at startup.
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    pub discord_bot_token: String,
+    pub openai_token: String,
+    pub openai_endpoint: String,
+    /// Sent as `OpenAI-Organization` / `OpenAI-Project` for billing attribution
+    pub openai_org: Option<String>,
+    pub openai_project: Option<String>,
+    pub gpt_engine: String,
+    pub gpt_models: Vec<String>,
+    pub model_state_file: String,
+    /// Where the `/set_max_tokens` value is persisted
+    pub max_tokens_state_file: String,
+    pub mistral_token: String,
+    pub mistral_endpoint: String,
+    pub mistral_engine: String,
+    pub cmc_key: String,
+    pub cmc_api: String,
+    /// Coin logo URL with `{id}` standing for the CMC id
+    pub cmc_icon_url: String,
+    pub max_symbols: usize,
+    /// Minimum time between price lookups of one user
+    pub cmc_cooldown: Duration,
+    /// CMC requests allowed in flight at once, others are turned away
+    pub max_cmc_requests: usize,
+    /// How long a CMC quote is reused before fetching it again
+    pub cmc_cache: Duration,
+    pub alerts_file: String,
+    /// JSON lines log of `/feedback` and 👍/👎 ratings
+    pub feedback_file: String,
+    pub alert_poll_interval: Duration,
+    pub reply_max_token: u16,
+    pub history_max_token: usize,
+    /// Stored messages per conversation besides system prompts, whatever their tokens
+    pub max_history_messages: usize,
+    /// Forget channels' conversations unused for this long, `None` to keep them
+    pub history_idle_expiry: Option<Duration>,
+    /// Keyed by model name or prefix, used by `/estimate`
+    pub model_prices: HashMap<String, ModelPrice>,
+    pub stream_edit_interval: Duration,
+    /// Posted under the header right away and edited into the reply, `None` to wait for the reply
+    pub thinking_placeholder: Option<String>,
+    /// Append the backend's system_fingerprint to chat replies
+    pub show_fingerprint: bool,
+    /// Token usage under every reply
+    pub show_usage: bool,
+    /// Note under a reply when older messages were dropped to fit the context window
+    pub notify_on_trim: bool,
+    /// Rewrite headers, tables and lists in replies into markdown Discord renders well
+    pub normalize_markdown: bool,
+    /// Reuse replies to repeated questions, only for seeded or zero temperature requests
+    pub enable_response_cache: bool,
+    pub response_cache_size: usize,
+    pub response_cache_ttl: Duration,
+    pub retry: RetryConfig,
+    /// Defaults for chat completions, `/chat` can override them per message
+    pub sampling: Sampling,
+    /// Sequences the model stops generating at, sent with every chat request
+    pub stop: Vec<String>,
+    /// Total time allowed for any upstream HTTP request, streamed completions included
+    pub http_timeout: Duration,
+    pub image_size: ImageSize,
+    /// Branding applied to every embed
+    pub embed_footer: Option<String>,
+    pub embed_footer_icon: Option<String>,
+    pub embed_color: u32,
+    /// Price embed colors for `/p` and `/crypto_chart`
+    pub change_colors: ChangeColors,
+    pub embed_thumbnail: Option<String>,
+    /// "Playing ..." status with `{model}` standing for the active engine, `None` for no status
+    pub activity: Option<String>,
+    pub image_count: u8,
+    pub user_cooldown: Duration,
+    /// Prefix for text commands next to mentioning the bot, `None` for mentions only
+    pub command_prefix: Option<String>,
+    /// Privileged gateway intents, each also has to be switched on in the developer portal.
+    /// Message content defaults to on when there is a `command_prefix`
+    pub message_content_intent: bool,
+    pub guild_members_intent: bool,
+    pub presence_intent: bool,
+    pub admin_user_ids: Vec<u64>,
+    pub admin_role_ids: Vec<u64>,
+    /// Channels to answer in for guilds without their own `/enable_here` list, empty for all
+    pub allowed_channel_ids: Vec<u64>,
+    pub emoji_file: String,
+    /// Translated command names and descriptions, see `locales.json`
+    pub locales_file: String,
+    pub guild_config_file: String,
+    /// Directory of `<name>.txt` system prompts picked per channel with `/persona`
+    pub personas_dir: String,
+    /// The built-in gpt and mistral providers followed by the ones in `PROVIDERS_FILE`
+    pub providers: Vec<ProviderConfig>,
+    /// Serve Prometheus metrics on this address when set
+    pub metrics_addr: Option<String>,
+    /// CSV file every completion's user, model, tokens and cost is appended to, `None` for no ledger
+    pub spend_ledger: Option<String>,
+    /// Check user input against the blocklist and OpenAI's moderation endpoint before sending it
+    pub enable_moderation: bool,
+    /// Regexes refused with `enable_moderation`, one per line
+    pub blocklist_file: String,
+    /// Register `/search`, which needs a `SEARCH_KEY`
+    pub enable_search: bool,
+    /// Brave Search API compatible web search endpoint
+    pub search_api: String,
+    pub search_key: Option<String>,
+    /// Results `/search` hands to the model
+    pub search_results: usize,
+    pub sharding: Sharding,
+}
+
+/// Every missing or invalid variable found while loading the config.
+#[derive(Debug)]
+pub struct ConfigError(pub Vec<String>);
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Invalid configuration:")?;
+        for problem in &self.0 {
+            writeln!(f, "  - {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    pub fn from_env() -> Result<Self, ConfigError> {
+        let mut errors = Vec::new();
+
+        let gpt_engine = required(&mut errors, "GPT_ENGINE");
+        let mut gpt_models: Vec<String> = match env::var("GPT_MODELS") {
+            Ok(v) => v
+                .split(',')
+                .map(|m| m.trim().to_string())
+                .filter(|m| !m.is_empty())
+                .collect(),
+            Err(_) => DEFAULT_GPT_MODELS.iter().map(|m| m.to_string()).collect(),
+        };
+        if !gpt_engine.is_empty() && !gpt_models.contains(&gpt_engine) {
+            gpt_models.push(gpt_engine.clone());
+        }
+
+        let mut config = Config {
+            discord_bot_token: required(&mut errors, "DISCORD_BOT_TOKEN"),
+            openai_token: required(&mut errors, "OPENAI_TOKEN"),
+            openai_endpoint: required(&mut errors, "OPENAI_ENDPOINT"),
+            openai_org: optional_string("OPENAI_ORG"),
+            openai_project: optional_string("OPENAI_PROJECT"),
+            gpt_engine,
+            gpt_models,
+            model_state_file: env::var("MODEL_STATE_FILE")
+                .unwrap_or_else(|_| "active_model.txt".to_string()),
+            max_tokens_state_file: env::var("MAX_TOKENS_STATE_FILE")
+                .unwrap_or_else(|_| "reply_max_token.txt".to_string()),
+            mistral_token: required(&mut errors, "MISTRAL_TOKEN"),
+            mistral_endpoint: required(&mut errors, "MISTRAL_ENDPOINT"),
+            mistral_engine: required(&mut errors, "MISTRAL_ENGINE"),
+            cmc_key: required(&mut errors, "CMC_KEY"),
+            cmc_api: env::var("CMC_API").unwrap_or_else(|_| {
+                "https://pro-api.coinmarketcap.com/v2/cryptocurrency/quotes/latest".to_string()
+            }),
+            cmc_icon_url: env::var("CMC_ICON_URL").unwrap_or_else(|_| {
+                "https://s2.coinmarketcap.com/static/img/coins/64x64/{id}.png".to_string()
+            }),
+            max_symbols: optional_parse(&mut errors, "MAX_SYMBOLS", 10),
+            cmc_cooldown: Duration::from_secs(optional_parse(&mut errors, "CMC_COOLDOWN_SECS", 3)),
+            max_cmc_requests: optional_parse(&mut errors, "MAX_CMC_REQUESTS", 2usize).max(1),
+            cmc_cache: Duration::from_secs(optional_parse(&mut errors, "CMC_CACHE_SECS", 60)),
+            alerts_file: env::var("ALERTS_FILE").unwrap_or_else(|_| "alerts.json".to_string()),
+            feedback_file: env::var("FEEDBACK_FILE")
+                .unwrap_or_else(|_| "feedback.jsonl".to_string()),
+            alert_poll_interval: Duration::from_secs(
+                optional_parse(&mut errors, "ALERT_POLL_SECS", 300u64).max(1),
+            ),
+            reply_max_token: required_parse(&mut errors, "REPLY_MAX_TOKEN"),
+            history_max_token: required_parse(&mut errors, "HISTORY_MAX_TOKEN"),
+            max_history_messages: optional_parse(&mut errors, "MAX_HISTORY_MESSAGES", 200),
+            history_idle_expiry: match optional_parse(&mut errors, "HISTORY_IDLE_EXPIRY_MINS", 1440)
+            {
+                0 => None,
+                mins => Some(Duration::from_secs(mins * 60)),
+            },
+            model_prices: model_prices(&mut errors),
+            stream_edit_interval: Duration::from_millis(optional_parse(
+                &mut errors,
+                "STREAM_EDIT_INTERVAL_MS",
+                750,
+            )),
+            thinking_placeholder: match env::var("THINKING_PLACEHOLDER") {
+                Ok(placeholder) => Some(placeholder.trim().to_string()).filter(|p| !p.is_empty()),
+                Err(_) => Some("🤔 thinking…".to_string()),
+            },
+            show_fingerprint: optional_parse(&mut errors, "SHOW_FINGERPRINT", false),
+            show_usage: optional_parse(&mut errors, "SHOW_USAGE", false),
+            notify_on_trim: optional_parse(&mut errors, "NOTIFY_ON_TRIM", false),
+            normalize_markdown: optional_parse(&mut errors, "NORMALIZE_MARKDOWN", true),
+            enable_response_cache: optional_parse(&mut errors, "ENABLE_RESPONSE_CACHE", false),
+            response_cache_size: optional_parse(&mut errors, "RESPONSE_CACHE_SIZE", 100),
+            response_cache_ttl: Duration::from_secs(optional_parse(
+                &mut errors,
+                "RESPONSE_CACHE_SECS",
+                600,
+            )),
+            retry: RetryConfig {
+                max_retries: optional_parse(&mut errors, "MAX_RETRIES", 3),
+                base_delay_ms: optional_parse(&mut errors, "RETRY_BASE_MS", 500),
+            },
+            sampling: Sampling {
+                top_p: optional_in_range(&mut errors, "TOP_P", TOP_P_RANGE),
+                frequency_penalty: optional_in_range(
+                    &mut errors,
+                    "FREQUENCY_PENALTY",
+                    PENALTY_RANGE,
+                ),
+                presence_penalty: optional_in_range(&mut errors, "PRESENCE_PENALTY", PENALTY_RANGE),
+            },
+            stop: match parse_stop_sequences(&env::var("STOP_SEQUENCES").unwrap_or_default()) {
+                Ok(stop) => stop,
+                Err(e) => {
+                    errors.push(format!("STOP_SEQUENCES: {}", e));
+                    Vec::new()
+                }
+            },
+            http_timeout: Duration::from_secs(
+                optional_parse(&mut errors, "HTTP_TIMEOUT_SECS", 120u64).max(1),
+            ),
+            image_size: image_size(&mut errors),
+            embed_footer: optional_string("EMBED_FOOTER"),
+            embed_footer_icon: optional_string("EMBED_FOOTER_ICON"),
+            embed_color: hex_color(&mut errors, "EMBED_COLOR", 0xF0CCD4),
+            change_colors: change_colors(&mut errors),
+            embed_thumbnail: optional_string("EMBED_THUMBNAIL"),
+            activity: match env::var("ACTIVITY") {
+                Ok(activity) => Some(activity.trim().to_string()).filter(|a| !a.is_empty()),
+                Err(_) => Some("with {model}".to_string()),
+            },
+            image_count: optional_parse(&mut errors, "IMAGE_COUNT", 1),
+            user_cooldown: Duration::from_secs(optional_parse(
+                &mut errors,
+                "USER_COOLDOWN_SECS",
+                10,
+            )),
+            command_prefix: match env::var("COMMAND_PREFIX") {
+                Ok(prefix) => Some(prefix.trim().to_string()).filter(|p| !p.is_empty()),
+                Err(_) => Some("!".to_string()),
+            },
+            // Defaults to whether there is a prefix, set below
+            message_content_intent: false,
+            guild_members_intent: optional_parse(&mut errors, "GUILD_MEMBERS_INTENT", false),
+            presence_intent: optional_parse(&mut errors, "PRESENCE_INTENT", false),
+            admin_user_ids: list_parse(&mut errors, "ADMIN_USER_IDS"),
+            admin_role_ids: list_parse(&mut errors, "ADMIN_ROLE_IDS"),
+            allowed_channel_ids: list_parse(&mut errors, "ALLOWED_CHANNEL_IDS"),
+            emoji_file: env::var("EMOJI_FILE").unwrap_or_else(|_| "emojis.json".to_string()),
+            locales_file: env::var("LOCALES_FILE").unwrap_or_else(|_| "locales.json".to_string()),
+            personas_dir: env::var("PERSONAS_DIR").unwrap_or_else(|_| "personas".to_string()),
+            guild_config_file: env::var("GUILD_CONFIG_FILE")
+                .unwrap_or_else(|_| "guild_config.json".to_string()),
+            providers: Vec::new(),
+            metrics_addr: env::var("METRICS_ADDR")
+                .ok()
+                .filter(|a| !a.trim().is_empty()),
+            spend_ledger: optional_string("SPEND_LEDGER_FILE"),
+            enable_moderation: optional_parse(&mut errors, "ENABLE_MODERATION", false),
+            blocklist_file: env::var("BLOCKLIST_FILE")
+                .unwrap_or_else(|_| "blocklist.txt".to_string()),
+            enable_search: optional_parse(&mut errors, "ENABLE_SEARCH", false),
+            search_api: env::var("SEARCH_API")
+                .unwrap_or_else(|_| "https://api.search.brave.com/res/v1/web/search".to_string()),
+            search_key: optional_string("SEARCH_KEY"),
+            search_results: optional_parse(&mut errors, "SEARCH_RESULTS", 5usize).clamp(1, 20),
+            sharding: Sharding::parse(&env::var("SHARD_COUNT").unwrap_or_default()).unwrap_or_else(
+                |e| {
+                    errors.push(e);
+                    Sharding::Single
+                },
+            ),
+        };
+        // Mentions carry their content without it, a plain prefix doesn't
+        config.message_content_intent = optional_parse(
+            &mut errors,
+            "MESSAGE_CONTENT_INTENT",
+            config.command_prefix.is_some(),
+        );
+        config.providers = vec![
+            ProviderConfig {
+                name: GPT_PROVIDER.to_string(),
+                endpoint: config.openai_endpoint.clone(),
+                token: config.openai_token.clone(),
+                org: config.openai_org.clone(),
+                project: config.openai_project.clone(),
+                engine: None,
+                tools: true,
+            },
+            ProviderConfig {
+                name: MISTRAL_PROVIDER.to_string(),
+                endpoint: config.mistral_endpoint.clone(),
+                token: config.mistral_token.clone(),
+                // Mistral has no organizations or projects
+                org: None,
+                project: None,
+                engine: Some(config.mistral_engine.clone()),
+                tools: false,
+            },
+        ];
+        let providers_file =
+            env::var("PROVIDERS_FILE").unwrap_or_else(|_| "providers.json".to_string());
+        for provider in load_providers(&mut errors, &providers_file) {
+            if config.providers.iter().any(|p| p.name == provider.name) {
+                errors.push(format!(
+                    "Provider {:?} in {} is defined more than once",
+                    provider.name, providers_file
+                ));
+            } else {
+                config.providers.push(provider);
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(config)
+        } else {
+            Err(ConfigError(errors))
+        }
+    }
+}
+
+/// An OpenAI compatible chat endpoint, e.g. Groq or a local llama.cpp server.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ProviderConfig {
+    pub name: String,
+    pub endpoint: String,
+    pub token: String,
+    /// `OpenAI-Organization` header, for OpenAI itself
+    #[serde(default)]
+    pub org: Option<String>,
+    /// `OpenAI-Project` header, for OpenAI itself
+    #[serde(default)]
+    pub project: Option<String>,
+    /// `None` follows `/models` and the guild's model override
+    #[serde(default)]
+    pub engine: Option<String>,
+    /// Offer the price tool to the model
+    #[serde(default)]
+    pub tools: bool,
+}
+
+/// Extra providers listed in `path`, which is optional.
+fn load_providers(errors: &mut Vec<String>, path: &str) -> Vec<ProviderConfig> {
+    let Ok(json) = std::fs::read_to_string(path) else {
+        return Vec::new();
+    };
+    let providers: Vec<ProviderConfig> = match serde_json::from_str(&json) {
+        Ok(providers) => providers,
+        Err(e) => {
+            errors.push(format!("{} is malformed: {}", path, e));
+            return Vec::new();
+        }
+    };
+    providers
+        .into_iter()
+        .filter(|provider| {
+            let problem = if provider.name.trim().is_empty() {
+                Some("has no name")
+            } else if provider.endpoint.trim().is_empty() {
+                Some("has no endpoint")
+            } else if provider
+                .engine
+                .as_deref()
+                .unwrap_or_default()
+                .trim()
+                .is_empty()
+            {
+                Some("has no engine")
+            } else {
+                None
+            };
+            if let Some(problem) = problem {
+                errors.push(format!(
+                    "Provider {:?} in {} {}",
+                    provider.name, path, problem
+                ));
+            }
+            problem.is_none()
+        })
+        .collect()
+}
+
+/// The personas in `dir`, each `<name>.txt` file holding a system prompt. The directory is optional.
+pub fn load_personas(dir: &str) -> BTreeMap<String, String> {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return BTreeMap::new();
+    };
+    let mut personas = BTreeMap::new();
+    for path in entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+    {
+        if path.extension().and_then(|e| e.to_str()) != Some("txt") {
+            continue;
+        }
+        let Some(name) = path.file_stem().and_then(|n| n.to_str()) else {
+            continue;
+        };
+        match std::fs::read_to_string(&path) {
+            Ok(prompt) if !prompt.trim().is_empty() => {
+                personas.insert(name.to_string(), prompt);
+            }
+            Ok(_) => warn!("Skipping empty persona {}", path.display()),
+            Err(e) => warn!("Can't read persona {}: {}", path.display(), e),
+        }
+    }
+    personas
+}
+
+/// Where chat answers are posted.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize, poise::ChoiceParameter,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum ReplyMode {
+    /// A plain message in the channel
+    #[default]
+    #[name = "channel"]
+    Channel,
+    /// A Discord reply to the invoking message, prefix commands only
+    #[name = "reply"]
+    Reply,
+    /// A thread started from the invoking message, prefix commands only
+    #[name = "thread"]
+    Thread,
+}
+
+/// Per-guild overrides of the global config, keyed by guild id in `GUILD_CONFIG_FILE`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct GuildConfig {
+    pub model: Option<String>,
+    pub reply_max_token: Option<u16>,
+    pub system_prompt: Option<String>,
+    pub reply_mode: Option<ReplyMode>,
+    /// Channels set with `/enable_here`, `None` falls back to `ALLOWED_CHANNEL_IDS`
+    pub allowed_channels: Option<Vec<u64>>,
+    /// Persona names keyed by channel id, replacing the system prompt there
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub channel_personas: HashMap<u64, String>,
+}
+
+impl GuildConfig {
+    /// Channels the chat and price commands answer in, empty meaning everywhere.
+    pub fn allowed_channels<'a>(&'a self, default: &'a [u64]) -> &'a [u64] {
+        self.allowed_channels.as_deref().unwrap_or(default)
+    }
+
+    pub fn load_all(path: &str) -> HashMap<u64, GuildConfig> {
+        match std::fs::read_to_string(path) {
+            Ok(json) => serde_json::from_str(&json).unwrap_or_else(|e| {
+                warn!("Ignoring malformed {}: {}", path, e);
+                HashMap::new()
+            }),
+            Err(_) => HashMap::new(),
+        }
+    }
+
+    pub fn save_all(path: &str, configs: &HashMap<u64, GuildConfig>) -> std::io::Result<()> {
+        std::fs::write(path, serde_json::to_string_pretty(configs)?)
+    }
+}
+
+/// `DEFAULT_MODEL_PRICES` overridden by `MODEL_PRICES`, e.g. `gpt-4=0.03:0.06,gpt-4o=0.005:0.015`.
+fn model_prices(errors: &mut Vec<String>) -> HashMap<String, ModelPrice> {
+    let mut prices: HashMap<String, ModelPrice> = DEFAULT_MODEL_PRICES
+        .iter()
+        .map(|(model, prompt, completion)| {
+            (
+                model.to_string(),
+                ModelPrice {
+                    prompt: *prompt,
+                    completion: *completion,
+                },
+            )
+        })
+        .collect();
+    let raw = env::var("MODEL_PRICES").unwrap_or_default();
+    for entry in raw.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let parsed = entry.split_once('=').and_then(|(model, price)| {
+            let (prompt, completion) = price.split_once(':')?;
+            Some((
+                model.trim().to_string(),
+                ModelPrice {
+                    prompt: prompt.trim().parse().ok()?,
+                    completion: completion.trim().parse().ok()?,
+                },
+            ))
+        });
+        match parsed {
+            Some((model, price)) => {
+                prices.insert(model, price);
+            }
+            None => errors.push(format!(
+                "MODEL_PRICES entries must look like model=prompt:completion, got {:?}",
+                entry
+            )),
+        }
+    }
+    prices
+}
+
+fn image_size(errors: &mut Vec<String>) -> ImageSize {
+    match env::var("IMAGE_SIZE").as_deref().map(str::trim) {
+        Err(_) | Ok("") | Ok("1024x1024") => ImageSize::S1024x1024,
+        Ok("256x256") => ImageSize::S256x256,
+        Ok("512x512") => ImageSize::S512x512,
+        Ok("1792x1024") => ImageSize::S1792x1024,
+        Ok("1024x1792") => ImageSize::S1024x1792,
+        Ok(other) => {
+            errors.push(format!(
+                "IMAGE_SIZE must be one of 256x256, 512x512, 1024x1024, 1792x1024, 1024x1792, got {:?}",
+                other
+            ));
+            ImageSize::S1024x1024
+        }
+    }
+}
+
+fn optional_string(key: &str) -> Option<String> {
+    env::var(key)
+        .ok()
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+}
+
+/// A `#RRGGBB` color.
+fn hex_color(errors: &mut Vec<String>, key: &str, default: u32) -> u32 {
+    let Some(raw) = optional_string(key) else {
+        return default;
+    };
+    let hex = raw.strip_prefix('#').unwrap_or(&raw);
+    match u32::from_str_radix(hex, 16) {
+        Ok(color) if hex.len() == 6 => color,
+        _ => {
+            errors.push(format!(
+                "{} must be a color like #F0CCD4, got {:?}",
+                key, raw
+            ));
+            default
+        }
+    }
+}
+
+fn change_colors(errors: &mut Vec<String>) -> ChangeColors {
+    let default = ChangeColors::default();
+    let mut threshold = optional_parse(errors, "CHANGE_THRESHOLD_PCT", default.threshold);
+    if !(threshold.is_finite() && threshold >= 0.) {
+        errors.push(format!(
+            "CHANGE_THRESHOLD_PCT must be a percentage of 0 or more, got {}",
+            threshold
+        ));
+        threshold = default.threshold;
+    }
+    ChangeColors {
+        threshold,
+        up: hex_color(errors, "UP_COLOR", default.up),
+        down: hex_color(errors, "DOWN_COLOR", default.down),
+        flat: hex_color(errors, "FLAT_COLOR", default.flat),
+    }
+}
+
+fn required(errors: &mut Vec<String>, key: &str) -> String {
+    match env::var(key) {
+        Ok(v) if !v.trim().is_empty() => v,
+        _ => {
+            errors.push(format!("{} is not set", key));
+            String::new()
+        }
+    }
+}
+
+fn required_parse<T: FromStr + Default>(errors: &mut Vec<String>, key: &str) -> T {
+    let raw = required(errors, key);
+    if raw.is_empty() {
+        return T::default();
+    }
+    parse(errors, key, &raw).unwrap_or_default()
+}
+
+fn optional_parse<T: FromStr>(errors: &mut Vec<String>, key: &str, default: T) -> T {
+    match env::var(key) {
+        Ok(raw) if !raw.trim().is_empty() => parse(errors, key, &raw).unwrap_or(default),
+        _ => default,
+    }
+}
+
+fn optional_in_range(
+    errors: &mut Vec<String>,
+    key: &str,
+    range: RangeInclusive<f32>,
+) -> Option<f32> {
+    let value: f32 = parse(errors, key, &optional_string(key)?)?;
+    if !range.contains(&value) {
+        errors.push(format!(
+            "{} must be between {} and {}, got {}",
+            key,
+            range.start(),
+            range.end(),
+            value
+        ));
+        return None;
+    }
+    Some(value)
+}
+
+/// Comma separated stop sequences, with `\n` standing for a line break.
+pub fn parse_stop_sequences(raw: &str) -> Result<Vec<String>, String> {
+    let stop: Vec<String> = raw
+        .split(',')
+        .map(|s| s.trim().replace("\\n", "\n"))
+        .filter(|s| !s.is_empty())
+        .collect();
+    if stop.len() > MAX_STOP_SEQUENCES {
+        return Err(format!(
+            "at most {} stop sequences are allowed, got {}",
+            MAX_STOP_SEQUENCES,
+            stop.len()
+        ));
+    }
+    Ok(stop)
+}
+
+fn list_parse<T: FromStr>(errors: &mut Vec<String>, key: &str) -> Vec<T> {
+    env::var(key)
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .filter_map(|v| parse(errors, key, v))
+        .collect()
+}
+
+fn parse<T: FromStr>(errors: &mut Vec<String>, key: &str, raw: &str) -> Option<T> {
+    match raw.trim().parse() {
+        Ok(v) => Some(v),
+        Err(_) => {
+            errors.push(format!("{} must be a number, got {:?}", key, raw));
+            None
+        }
+    }
+}
@@ -0,0 +1,76 @@
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, poise::ChoiceParameter)]
+#[serde(rename_all = "lowercase")]
+pub enum Rating {
+    #[name = "good"]
+    Good,
+    #[name = "bad"]
+    Bad,
+}
+
+/// A user's rating of a reply, one JSON object per line in `FEEDBACK_FILE`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Feedback {
+    /// Unix seconds
+    pub timestamp: u64,
+    pub user_id: u64,
+    pub rating: Rating,
+    pub note: Option<String>,
+    pub provider: String,
+    pub channel_id: u64,
+    /// The exchange that was rated, cut short
+    pub question: Option<String>,
+    pub answer: Option<String>,
+}
+
+/// Append `feedback` to the log at `path`.
+pub fn append(path: &str, feedback: &Feedback) -> std::io::Result<()> {
+    let mut line = serde_json::to_string(feedback)?;
+    line.push('\n');
+    std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?
+        .write_all(line.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn append_writes_one_json_line_per_rating() {
+        let path = std::env::temp_dir().join(format!("feedback-test-{}.jsonl", std::process::id()));
+        let path = path.to_str().unwrap();
+        let _ = std::fs::remove_file(path);
+        let good = Feedback {
+            timestamp: 1700000000,
+            user_id: 42,
+            rating: Rating::Good,
+            note: Some("cute and correct".to_string()),
+            provider: "gpt".to_string(),
+            channel_id: 7,
+            question: Some("hi".to_string()),
+            answer: Some("Hello master ～".to_string()),
+        };
+        let bad = Feedback {
+            rating: Rating::Bad,
+            note: None,
+            ..good.clone()
+        };
+
+        append(path, &good).unwrap();
+        append(path, &bad).unwrap();
+
+        let log = std::fs::read_to_string(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        let lines: Vec<Feedback> = log
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines, vec![good, bad]);
+        assert!(log.contains("\"rating\":\"bad\""));
+    }
+}
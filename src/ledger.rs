@@ -0,0 +1,172 @@
+use std::{collections::HashMap, io::Write};
+use tracing::warn;
+
+const HEADER: &str = "timestamp,user_id,model,prompt_tokens,completion_tokens,cost_usd";
+
+/// One completion in `SPEND_LEDGER_FILE`, a CSV file that is only ever appended to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    /// Unix seconds
+    pub timestamp: u64,
+    /// 0 when nobody in particular asked, e.g. for scheduled jobs
+    pub user_id: u64,
+    pub model: String,
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
+    /// `None` when `MODEL_PRICES` has no price for the model
+    pub cost: Option<f64>,
+}
+
+impl Entry {
+    fn to_csv(&self) -> String {
+        format!(
+            "{},{},{},{},{},{}\n",
+            self.timestamp,
+            self.user_id,
+            self.model.replace(',', " "),
+            self.prompt_tokens,
+            self.completion_tokens,
+            self.cost
+                .map_or(String::new(), |cost| format!("{:.6}", cost))
+        )
+    }
+
+    fn from_csv(line: &str) -> Option<Entry> {
+        let fields: Vec<&str> = line.trim().split(',').collect();
+        let [timestamp, user_id, model, prompt_tokens, completion_tokens, cost] = fields[..] else {
+            return None;
+        };
+        Some(Entry {
+            timestamp: timestamp.parse().ok()?,
+            user_id: user_id.parse().ok()?,
+            model: model.to_string(),
+            prompt_tokens: prompt_tokens.parse().ok()?,
+            completion_tokens: completion_tokens.parse().ok()?,
+            cost: match cost {
+                "" => None,
+                cost => Some(cost.parse().ok()?),
+            },
+        })
+    }
+}
+
+/// Append `entry` to the ledger at `path`, starting it with a header row when it is new.
+pub fn append(path: &str, entry: &Entry) -> std::io::Result<()> {
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    let mut line = entry.to_csv();
+    if file.metadata()?.len() == 0 {
+        line = format!("{}\n{}", HEADER, line);
+    }
+    // One write per entry so concurrent appends don't interleave
+    file.write_all(line.as_bytes())
+}
+
+/// Every entry of a ledger's contents, skipping the header and lines that don't parse.
+pub fn parse(csv: &str) -> Vec<Entry> {
+    csv.lines()
+        .filter(|line| !line.trim().is_empty() && *line != HEADER)
+        .filter_map(|line| {
+            let entry = Entry::from_csv(line);
+            if entry.is_none() {
+                warn!("Skipping malformed ledger line {:?}", line);
+            }
+            entry
+        })
+        .collect()
+}
+
+/// What one user spent in a time window.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct UserSpend {
+    pub requests: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub cost: f64,
+    /// Completions of models without a known price, left out of `cost`
+    pub unpriced: u64,
+}
+
+/// Per-user totals of the entries at or after `since`, biggest spenders first.
+pub fn summarize(entries: &[Entry], since: u64) -> Vec<(u64, UserSpend)> {
+    let mut users: HashMap<u64, UserSpend> = HashMap::new();
+    for entry in entries.iter().filter(|e| e.timestamp >= since) {
+        let spend = users.entry(entry.user_id).or_default();
+        spend.requests += 1;
+        spend.prompt_tokens += u64::from(entry.prompt_tokens);
+        spend.completion_tokens += u64::from(entry.completion_tokens);
+        match entry.cost {
+            Some(cost) => spend.cost += cost,
+            None => spend.unpriced += 1,
+        }
+    }
+    let mut users: Vec<(u64, UserSpend)> = users.into_iter().collect();
+    users.sort_by(|a, b| b.1.cost.total_cmp(&a.1.cost).then(a.0.cmp(&b.0)));
+    users
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(timestamp: u64, user_id: u64, cost: Option<f64>) -> Entry {
+        Entry {
+            timestamp,
+            user_id,
+            model: "gpt-4".to_string(),
+            prompt_tokens: 100,
+            completion_tokens: 50,
+            cost,
+        }
+    }
+
+    #[test]
+    fn entries_round_trip_through_csv() {
+        let priced = entry(1700000000, 42, Some(0.0045));
+        let unpriced = entry(1700000001, 0, None);
+        let csv = format!("{}\n{}{}", HEADER, priced.to_csv(), unpriced.to_csv());
+        assert_eq!(parse(&csv), vec![priced, unpriced]);
+        assert_eq!(parse("1,2,gpt-4,3\nnot,a,ledger,line,at,all\n"), Vec::new());
+    }
+
+    #[test]
+    fn append_writes_the_header_once() {
+        let path = std::env::temp_dir().join(format!("ledger-test-{}.csv", std::process::id()));
+        let path = path.to_str().unwrap();
+        let _ = std::fs::remove_file(path);
+
+        append(path, &entry(1, 1, Some(0.5))).unwrap();
+        append(path, &entry(2, 2, None)).unwrap();
+
+        let csv = std::fs::read_to_string(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(csv.matches(HEADER).count(), 1);
+        assert_eq!(parse(&csv).len(), 2);
+    }
+
+    #[test]
+    fn summarize_totals_per_user_in_the_window() {
+        let entries = vec![
+            entry(10, 1, Some(0.01)),
+            entry(20, 1, Some(0.02)),
+            entry(20, 2, Some(0.5)),
+            entry(30, 1, None),
+            entry(5, 2, Some(9.0)),
+        ];
+
+        let summary = summarize(&entries, 10);
+
+        assert_eq!(summary.len(), 2);
+        assert_eq!(summary[0].0, 2);
+        assert_eq!(summary[0].1.requests, 1);
+        let (user, spend) = &summary[1];
+        assert_eq!(*user, 1);
+        assert_eq!(spend.requests, 3);
+        assert_eq!(spend.prompt_tokens, 300);
+        assert_eq!(spend.unpriced, 1);
+        assert!((spend.cost - 0.03).abs() < 1e-9);
+        assert!(summarize(&entries, 31).is_empty());
+    }
+}
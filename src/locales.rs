@@ -0,0 +1,88 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use tracing::warn;
+
+/// A command's name and description in one Discord locale, e.g. `zh-CN` or `ja`.
+#[derive(Debug, Default, Deserialize)]
+pub struct Localization {
+    pub name: Option<String>,
+    pub description: Option<String>,
+}
+
+/// Translations from `LOCALES_FILE`, keyed by qualified command name and then by locale.
+pub type Locales = HashMap<String, HashMap<String, Localization>>;
+
+pub fn load(path: &str) -> Locales {
+    match std::fs::read_to_string(path) {
+        Ok(json) => serde_json::from_str(&json).unwrap_or_else(|e| {
+            warn!("Ignoring malformed {}: {}", path, e);
+            Locales::new()
+        }),
+        Err(e) => {
+            warn!("Can't read {}, commands stay in English: {}", path, e);
+            Locales::new()
+        }
+    }
+}
+
+/// Add the translations of `commands` and their subcommands, which Discord shows to users
+/// with a matching client language.
+pub fn apply<U, E>(commands: &mut [poise::Command<U, E>], locales: &Locales) {
+    for command in commands {
+        if let Some(translations) = locales.get(&command.qualified_name) {
+            for (locale, localization) in translations {
+                if let Some(name) = &localization.name {
+                    command
+                        .name_localizations
+                        .insert(locale.clone(), name.clone());
+                }
+                if let Some(description) = &localization.description {
+                    command
+                        .description_localizations
+                        .insert(locale.clone(), description.clone());
+                }
+            }
+        }
+        apply(&mut command.subcommands, locales);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn apply_localizes_commands_and_subcommands() {
+        let locales: Locales = serde_json::from_str(
+            r#"{
+                "chat": {"ja": {"description": "SocksGPT とチャット"}},
+                "guild model": {"zh-CN": {"name": "模型", "description": "设置模型"}}
+            }"#,
+        )
+        .unwrap();
+        let mut commands = vec![poise::Command::<(), ()> {
+            qualified_name: "guild".to_string(),
+            subcommands: vec![poise::Command {
+                qualified_name: "guild model".to_string(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        }];
+        commands.push(poise::Command {
+            qualified_name: "chat".to_string(),
+            ..Default::default()
+        });
+
+        apply(&mut commands, &locales);
+
+        assert!(commands[0].description_localizations.is_empty());
+        let model = &commands[0].subcommands[0];
+        assert_eq!(model.name_localizations["zh-CN"], "模型");
+        assert_eq!(model.description_localizations["zh-CN"], "设置模型");
+        assert_eq!(
+            commands[1].description_localizations["ja"],
+            "SocksGPT とチャット"
+        );
+        assert!(commands[1].name_localizations.is_empty());
+    }
+}
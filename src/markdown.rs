@@ -0,0 +1,139 @@
+/// Rewrite model markdown into what Discord renders well: headers become bold lines, tables
+/// go into a code block and list items get a consistent `-` marker and two-space nesting.
+/// Code blocks are left untouched.
+pub fn normalize(text: &str) -> String {
+    let mut out: Vec<String> = Vec::new();
+    let mut in_code = false;
+    let mut in_table = false;
+    // Indents of the list levels open above the current line
+    let mut list_indents: Vec<usize> = Vec::new();
+
+    for line in text.split('\n') {
+        let trimmed = line.trim_start();
+        if trimmed.starts_with("```") {
+            close_table(&mut out, &mut in_table);
+            list_indents.clear();
+            in_code = !in_code;
+            out.push(line.to_string());
+            continue;
+        }
+        if in_code {
+            out.push(line.to_string());
+            continue;
+        }
+
+        if trimmed.starts_with('|') {
+            if !in_table {
+                out.push("```".to_string());
+                in_table = true;
+            }
+            out.push(trimmed.trim_end().to_string());
+            continue;
+        }
+        close_table(&mut out, &mut in_table);
+
+        if let Some(title) = header_title(trimmed) {
+            list_indents.clear();
+            out.push(format!("**{}**", title));
+        } else if let Some((indent, item)) = list_item(line) {
+            while list_indents.last().is_some_and(|last| *last > indent) {
+                list_indents.pop();
+            }
+            if list_indents.last() != Some(&indent) {
+                list_indents.push(indent);
+            }
+            let depth = list_indents.len() - 1;
+            out.push(format!("{}{}", "  ".repeat(depth), item));
+        } else {
+            if !trimmed.is_empty() && trimmed.len() == line.len() {
+                list_indents.clear();
+            }
+            out.push(line.to_string());
+        }
+    }
+    // Leave a table cut off mid-stream readable too
+    close_table(&mut out, &mut in_table);
+    out.join("\n")
+}
+
+fn close_table(out: &mut Vec<String>, in_table: &mut bool) {
+    if *in_table {
+        out.push("```".to_string());
+        *in_table = false;
+    }
+}
+
+/// The text of a `#` to `######` header, without emphasis that would clash with the bold.
+fn header_title(line: &str) -> Option<String> {
+    let rest = line.trim_start_matches('#');
+    let level = line.len() - rest.len();
+    if !(1..=6).contains(&level) || !rest.starts_with(' ') {
+        return None;
+    }
+    let title = rest.trim().trim_end_matches('#').trim().replace("**", "");
+    (!title.is_empty()).then_some(title)
+}
+
+/// The indent width (tabs count as four) and the item rewritten with a `-` marker, for
+/// bulleted and numbered list lines.
+fn list_item(line: &str) -> Option<(usize, String)> {
+    let trimmed = line.trim_start();
+    let indent: usize = line[..line.len() - trimmed.len()]
+        .chars()
+        .map(|c| if c == '\t' { 4 } else { 1 })
+        .sum();
+    for marker in ["- ", "* ", "+ "] {
+        if let Some(item) = trimmed.strip_prefix(marker) {
+            return Some((indent, format!("- {}", item.trim_start())));
+        }
+    }
+    let digits = trimmed.len()
+        - trimmed
+            .trim_start_matches(|c: char| c.is_ascii_digit())
+            .len();
+    let rest = &trimmed[digits..];
+    if digits > 0 && (rest.starts_with(". ") || rest.starts_with(") ")) {
+        return Some((
+            indent,
+            format!("{}. {}", &trimmed[..digits], rest[2..].trim_start()),
+        ));
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn headers_become_bold_lines() {
+        assert_eq!(
+            normalize("### **Price** overview\nBTC is up"),
+            "**Price overview**\nBTC is up"
+        );
+        assert_eq!(normalize("#hashtag"), "#hashtag");
+    }
+
+    #[test]
+    fn tables_go_into_code_blocks() {
+        assert_eq!(
+            normalize("Prices:\n| Coin | Price |\n|---|---|\n| BTC | 1 |\nDone"),
+            "Prices:\n```\n| Coin | Price |\n|---|---|\n| BTC | 1 |\n```\nDone"
+        );
+        assert_eq!(normalize("| a |"), "```\n| a |\n```");
+    }
+
+    #[test]
+    fn nested_lists_get_consistent_markers_and_indents() {
+        assert_eq!(
+            normalize("* one\n    + nested\n\t\t* deeper\n    - nested again\n2) two"),
+            "- one\n  - nested\n    - deeper\n  - nested again\n2. two"
+        );
+    }
+
+    #[test]
+    fn code_blocks_are_left_alone() {
+        let text = "```md\n# not a header\n| not | a table |\n```";
+        assert_eq!(normalize(text), text);
+    }
+}
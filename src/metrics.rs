@@ -0,0 +1,157 @@
+use std::{collections::HashMap, fmt::Write, sync::Arc, sync::Mutex, time::Duration};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+use tracing::{info, warn};
+
+/// Metric name, help text and how to read it from the stats.
+type Counter = (&'static str, &'static str, fn(&ModelStats) -> u64);
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ModelStats {
+    pub requests: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub latency_ms: u64,
+}
+
+impl ModelStats {
+    pub fn avg_latency_ms(&self) -> u64 {
+        self.latency_ms / self.requests.max(1)
+    }
+
+    pub fn avg_tokens(&self) -> u64 {
+        (self.prompt_tokens + self.completion_tokens) / self.requests.max(1)
+    }
+}
+
+/// Token usage and latency of every completion, per model.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    models: Mutex<HashMap<String, ModelStats>>,
+}
+
+impl Metrics {
+    pub fn record(
+        &self,
+        model: &str,
+        prompt_tokens: u64,
+        completion_tokens: u64,
+        latency: Duration,
+    ) {
+        let mut models = self.models.lock().unwrap();
+        let stats = models.entry(model.to_string()).or_default();
+        stats.requests += 1;
+        stats.prompt_tokens += prompt_tokens;
+        stats.completion_tokens += completion_tokens;
+        stats.latency_ms += latency.as_millis() as u64;
+    }
+
+    /// Per-model stats sorted by model name.
+    pub fn snapshot(&self) -> Vec<(String, ModelStats)> {
+        let mut stats: Vec<(String, ModelStats)> = self
+            .models
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(model, stats)| (model.clone(), stats.clone()))
+            .collect();
+        stats.sort_by(|a, b| a.0.cmp(&b.0));
+        stats
+    }
+
+    /// Render the counters in the Prometheus text exposition format.
+    pub fn prometheus(&self) -> String {
+        let snapshot = self.snapshot();
+        let mut out = String::new();
+        let counters: [Counter; 4] = [
+            ("requests", "Chat completions", |s| s.requests),
+            ("prompt_tokens", "Prompt tokens sent", |s| s.prompt_tokens),
+            ("completion_tokens", "Completion tokens received", |s| {
+                s.completion_tokens
+            }),
+            (
+                "latency_ms",
+                "Completion wall-clock time in milliseconds",
+                |s| s.latency_ms,
+            ),
+        ];
+        for (name, help, value) in counters {
+            let _ = writeln!(out, "# HELP gpt_bot_{}_total {}", name, help);
+            let _ = writeln!(out, "# TYPE gpt_bot_{}_total counter", name);
+            for (model, stats) in &snapshot {
+                let _ = writeln!(
+                    out,
+                    "gpt_bot_{}_total{{model=\"{}\"}} {}",
+                    name,
+                    model,
+                    value(stats)
+                );
+            }
+        }
+        out
+    }
+}
+
+/// Pause after a failed `accept` before trying again.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Serve `metrics` as Prometheus text on every request to `addr`.
+pub async fn serve(addr: String, metrics: Arc<Metrics>) {
+    let listener = match TcpListener::bind(&addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            warn!("Can't bind metrics endpoint on {}: {}", addr, e);
+            return;
+        }
+    };
+    info!("Serving metrics on http://{}/metrics", addr);
+    loop {
+        let mut socket = match listener.accept().await {
+            Ok((socket, _)) => socket,
+            Err(e) => {
+                // Usually out of file descriptors, which won't clear up right away
+                warn!("Metrics endpoint can't accept a connection: {}", e);
+                tokio::time::sleep(ACCEPT_BACKOFF).await;
+                continue;
+            }
+        };
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+            let _ = socket.read(&mut buf).await;
+            let body = metrics.prometheus();
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            let _ = socket.write_all(response.as_bytes()).await;
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record_accumulates_per_model() {
+        let metrics = Metrics::default();
+        metrics.record("gpt-4", 100, 20, Duration::from_millis(300));
+        metrics.record("gpt-4", 50, 10, Duration::from_millis(100));
+        metrics.record("mistral-medium", 10, 5, Duration::from_millis(50));
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.len(), 2);
+        assert_eq!(snapshot[0].0, "gpt-4");
+        assert_eq!(snapshot[0].1.requests, 2);
+        assert_eq!(snapshot[0].1.avg_latency_ms(), 200);
+        assert_eq!(snapshot[0].1.avg_tokens(), 90);
+
+        let text = metrics.prometheus();
+        assert!(text.contains("gpt_bot_requests_total{model=\"gpt-4\"} 2"));
+        assert!(text.contains("gpt_bot_prompt_tokens_total{model=\"mistral-medium\"} 10"));
+    }
+}
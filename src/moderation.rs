@@ -0,0 +1,124 @@
+use regex::Regex;
+use serde::Deserialize;
+use serde_json::Value;
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+};
+use tracing::warn;
+
+/// Case-insensitive patterns from `BLOCKLIST_FILE`, one per line. Blank lines and lines starting
+/// with `#` are skipped, as are patterns that don't compile.
+pub fn parse_blocklist(text: &str) -> Vec<Regex> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| match Regex::new(&format!("(?i){}", line)) {
+            Ok(pattern) => Some(pattern),
+            Err(e) => {
+                warn!("Skipping invalid blocklist pattern {:?}: {}", line, e);
+                None
+            }
+        })
+        .collect()
+}
+
+/// The blocklist at `path`, empty when there is none.
+pub fn load_blocklist(path: &str) -> Vec<Regex> {
+    match std::fs::read_to_string(path) {
+        Ok(text) => parse_blocklist(&text),
+        Err(_) => Vec::new(),
+    }
+}
+
+/// The first pattern of `blocklist` that `text` matches.
+pub fn blocked_by<'a>(blocklist: &'a [Regex], text: &str) -> Option<&'a Regex> {
+    blocklist.iter().find(|pattern| pattern.is_match(text))
+}
+
+/// A short fingerprint of `text`, so flagged inputs can be told apart in the logs without
+/// keeping what was said.
+pub fn input_hash(text: &str) -> String {
+    let mut hasher = DefaultHasher::new();
+    text.hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
+/// The part of an OpenAI moderation response the bot reads.
+#[derive(Debug, Deserialize)]
+pub struct ModerationResponse {
+    pub results: Vec<ModerationResult>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ModerationResult {
+    pub flagged: bool,
+    /// Category name to whether the input falls into it
+    #[serde(default)]
+    pub categories: Value,
+}
+
+impl ModerationResponse {
+    /// Sorted names of the categories of every flagged result, `None` when nothing was flagged.
+    pub fn flagged_categories(&self) -> Option<Vec<String>> {
+        if !self.results.iter().any(|result| result.flagged) {
+            return None;
+        }
+        let mut categories: Vec<String> = self
+            .results
+            .iter()
+            .filter(|result| result.flagged)
+            .filter_map(|result| result.categories.as_object())
+            .flat_map(|categories| {
+                categories
+                    .iter()
+                    .filter(|(_, flagged)| flagged.as_bool() == Some(true))
+                    .map(|(name, _)| name.clone())
+            })
+            .collect();
+        categories.sort();
+        categories.dedup();
+        Some(categories)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blocklist_skips_comments_and_invalid_patterns() {
+        let blocklist = parse_blocklist(
+            "# jailbreaks\nignore (all )?previous instructions\n\n  DAN mode  \n(unclosed\n",
+        );
+        assert_eq!(blocklist.len(), 2);
+        assert!(blocked_by(&blocklist, "Please IGNORE ALL PREVIOUS INSTRUCTIONS").is_some());
+        assert!(blocked_by(&blocklist, "enable dan mode").is_some());
+        assert!(blocked_by(&blocklist, "what's the price of BTC?").is_none());
+    }
+
+    #[test]
+    fn input_hash_is_stable_and_short() {
+        assert_eq!(input_hash("hi"), input_hash("hi"));
+        assert_ne!(input_hash("hi"), input_hash("hi!"));
+        assert_eq!(input_hash("hi").len(), 16);
+    }
+
+    #[test]
+    fn flagged_categories_lists_what_was_flagged() {
+        let response: ModerationResponse = serde_json::from_str(
+            r#"{"results": [{"flagged": true, "categories": {"violence": true, "hate": false, "harassment": true}}]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            response.flagged_categories(),
+            Some(vec!["harassment".to_string(), "violence".to_string()])
+        );
+
+        let clean: ModerationResponse = serde_json::from_str(
+            r#"{"results": [{"flagged": false, "categories": {"violence": false}}]}"#,
+        )
+        .unwrap();
+        assert_eq!(clean.flagged_categories(), None);
+    }
+}
@@ -0,0 +1,330 @@
+use currency_rs::{Currency, CurrencyOpts};
+use regex::Regex;
+
+pub fn sanitize_input(input: &str) -> String {
+    // Define the regex pattern
+    let pattern = Regex::new(r"^[a-zA-Z0-9_-]{1,64}$").unwrap();
+
+    // Check if the input matches the regex pattern
+    if pattern.is_match(input) {
+        // If it matches, return the input unchanged
+        String::from(input)
+    } else {
+        // If it doesn't match, remove invalid characters and return the modified string
+        let modified_string: String = input
+            .chars()
+            .filter(|c| c.is_ascii_alphanumeric() || *c == '_' || *c == '-')
+            .collect();
+
+        modified_string
+    }
+}
+
+/// Replace every emoji shortcode in `message`, in the order the replacements are listed.
+pub fn replace_emoji(mut message: String, replacements: &[(String, String)]) -> String {
+    for (search, replace) in replacements {
+        message = message.replace(search, replace);
+    }
+
+    message
+}
+
+fn is_fence(line: &str) -> bool {
+    line.trim_start().starts_with("```")
+}
+
+/// Byte index at which to cut `s` so the head has at most `max_chars` chars,
+/// preferring the position right after the last space.
+fn split_point(s: &str, max_chars: usize) -> usize {
+    let end = s
+        .char_indices()
+        .nth(max_chars.max(1))
+        .map_or(s.len(), |(i, _)| i);
+    match s[..end].rfind(' ') {
+        Some(i) if i > 0 => i + 1,
+        _ => end,
+    }
+}
+
+/// Split `text` into messages of at most `limit` chars, breaking on line and then word
+/// boundaries. A ``` fence open at a boundary is closed and reopened so each chunk renders
+/// on its own.
+pub fn split_message(text: &str, limit: usize) -> Vec<String> {
+    fn flush(
+        current: &mut String,
+        header_len: &mut usize,
+        fence: &Option<String>,
+        chunks: &mut Vec<String>,
+    ) {
+        if fence.is_some() {
+            if !current.ends_with('\n') {
+                current.push('\n');
+            }
+            current.push_str("```");
+        }
+        chunks.push(std::mem::take(current));
+        if let Some(open) = fence {
+            current.push_str(open);
+            current.push('\n');
+        }
+        *header_len = current.chars().count();
+    }
+
+    let mut chunks = Vec::new();
+    let mut current = String::new();
+    let mut header_len = 0;
+    let mut fence: Option<String> = None;
+
+    for line in text.split_inclusive('\n') {
+        let closes_fence = fence.is_some() && is_fence(line);
+        let mut rest = line;
+        while !rest.is_empty() {
+            let reserve = if fence.is_some() && !closes_fence {
+                "\n```".len()
+            } else {
+                0
+            };
+            let used = current.chars().count();
+            let room = limit.saturating_sub(used + reserve);
+            if rest.chars().count() <= room {
+                current.push_str(rest);
+                rest = "";
+            } else if used > header_len {
+                flush(&mut current, &mut header_len, &fence, &mut chunks);
+            } else {
+                let cut = split_point(rest, room);
+                current.push_str(&rest[..cut]);
+                rest = &rest[cut..];
+                flush(&mut current, &mut header_len, &fence, &mut chunks);
+            }
+        }
+        if is_fence(line) {
+            fence = match fence {
+                Some(_) => None,
+                None => Some(line.trim().to_string()),
+            };
+        }
+    }
+    if current.chars().count() > header_len {
+        chunks.push(current);
+    }
+    chunks
+}
+
+/// Format a price with fewer decimals the bigger it is, e.g. `0.00001234` keeps 9 and
+/// `1,234.57` keeps 2. Negative amounts get the same precision as their positive counterpart,
+/// and a value on a boundary uses the precision of the range it starts.
+pub fn format_currency(num: f64) -> String {
+    let precision = match num.abs() {
+        m if m < 0.0001 => 9,
+        m if m < 0.001 => 8,
+        m if m < 0.01 => 7,
+        m if m < 0.1 => 6,
+        m if m < 1.0 => 5,
+        m if m < 10.0 => 4,
+        m if m < 100.0 => 3,
+        m if m < 1e5 => 2,
+        m if m < 1e6 => 1,
+        _ => 0,
+    };
+    let otp = CurrencyOpts::new().set_symbol("").set_precision(precision);
+    Currency::new_float(num, Some(otp)).format()
+}
+
+pub fn format_pct(num: f64) -> String {
+    let otp = CurrencyOpts::new().set_symbol("").set_precision(2);
+    Currency::new_float(num, Some(otp)).format()
+}
+
+/// Embed colors for price moves, and the % change that counts as a move.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChangeColors {
+    pub threshold: f64,
+    pub up: u32,
+    pub down: u32,
+    pub flat: u32,
+}
+
+impl Default for ChangeColors {
+    fn default() -> Self {
+        ChangeColors {
+            threshold: 1.,
+            up: 0x10CC84,
+            down: 0xF6465D,
+            flat: 0xF0CCD4,
+        }
+    }
+}
+
+pub fn up_or_down_color(num: f64, colors: &ChangeColors) -> u32 {
+    if num >= colors.threshold {
+        colors.up
+    } else if num <= -colors.threshold {
+        colors.down
+    } else {
+        colors.flat
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Digits after the decimal point, i.e. the precision `format_currency` picked.
+    fn decimals(formatted: &str) -> usize {
+        formatted.split('.').nth(1).map_or(0, str::len)
+    }
+
+    #[test]
+    fn sanitize_input_keeps_valid_names() {
+        assert_eq!(sanitize_input("BTC"), "BTC");
+        assert_eq!(sanitize_input("socks_the-maid"), "socks_the-maid");
+    }
+
+    #[test]
+    fn sanitize_input_drops_invalid_chars() {
+        assert_eq!(sanitize_input("b t/c😺"), "btc");
+        assert_eq!(sanitize_input("😺"), "");
+        assert_eq!(sanitize_input(""), "");
+    }
+
+    #[test]
+    fn replace_emoji_at_boundaries() {
+        let replacements = vec![
+            (":nya:".to_string(), "<:nya:1>".to_string()),
+            (":paw:".to_string(), "<:paw:2>".to_string()),
+        ];
+        assert_eq!(
+            replace_emoji(":nya: hi master :paw:".to_string(), &replacements),
+            "<:nya:1> hi master <:paw:2>"
+        );
+        assert_eq!(
+            replace_emoji(":nya::nya:".to_string(), &replacements),
+            "<:nya:1><:nya:1>"
+        );
+        assert_eq!(replace_emoji(":nya".to_string(), &replacements), ":nya");
+        assert_eq!(replace_emoji(String::new(), &replacements), "");
+    }
+
+    #[test]
+    fn split_message_short_text_is_one_chunk() {
+        assert_eq!(split_message("hi master", 30), vec!["hi master"]);
+        assert!(split_message("", 30).is_empty());
+    }
+    #[test]
+    fn split_message_breaks_on_words() {
+        let text = "nya ".repeat(100);
+        let chunks = split_message(&text, 30);
+        assert!(chunks.len() > 1);
+        for chunk in &chunks {
+            assert!(chunk.chars().count() <= 30);
+            assert!(chunk.split_whitespace().all(|word| word == "nya"));
+        }
+        assert_eq!(chunks.concat(), text);
+    }
+
+    #[test]
+    fn split_message_never_cuts_inside_an_emoji() {
+        let text = "😺".repeat(100);
+        let chunks = split_message(&text, 30);
+        assert_eq!(chunks.len(), 4);
+        for chunk in &chunks {
+            assert!(chunk.chars().count() <= 30);
+        }
+        assert_eq!(chunks.concat(), text);
+    }
+
+    #[test]
+    fn format_currency_groups_thousands() {
+        assert_eq!(format_currency(1234.5678), "1,234.57");
+        assert_eq!(format_currency(50_000.0), "50,000.00");
+    }
+
+    #[test]
+    fn format_currency_precision_shrinks_as_prices_grow() {
+        assert_eq!(decimals(&format_currency(0.00001234)), 9);
+        assert_eq!(decimals(&format_currency(0.5)), 5);
+        assert_eq!(decimals(&format_currency(42.0)), 3);
+        assert_eq!(decimals(&format_currency(500_000.0)), 1);
+        assert_eq!(decimals(&format_currency(2e12)), 0);
+    }
+
+    #[test]
+    fn format_currency_negative_amounts_mirror_positive_ones() {
+        assert_eq!(format_currency(-1234.5678), "-1,234.57");
+        for num in [0.00005, 0.0005, 0.005, 0.05, 0.5, 5.0, 50.0, 5e4, 5e5, 5e7] {
+            assert_eq!(
+                decimals(&format_currency(-num)),
+                decimals(&format_currency(num)),
+                "{}",
+                num
+            );
+        }
+        assert_eq!(decimals(&format_currency(-0.5)), 5);
+        assert_eq!(decimals(&format_currency(-2e9)), 0);
+    }
+
+    #[test]
+    fn format_currency_boundaries_start_the_next_range() {
+        for (num, precision) in [
+            (0.0001, 8),
+            (0.001, 7),
+            (0.01, 6),
+            (0.1, 5),
+            (1.0, 4),
+            (10.0, 3),
+            (100.0, 2),
+            (1e5, 1),
+            (1e6, 0),
+        ] {
+            assert_eq!(decimals(&format_currency(num)), precision, "{}", num);
+            assert_eq!(decimals(&format_currency(-num)), precision, "-{}", num);
+        }
+        assert_eq!(format_currency(1.0), "1.0000");
+        assert_eq!(format_currency(100.0), "100.00");
+    }
+
+    #[test]
+    fn format_currency_zero() {
+        assert_eq!(format_currency(0.0), "0.000000000");
+    }
+
+    #[test]
+    fn format_pct_rounds_to_two_places() {
+        assert_eq!(format_pct(1.98765), "1.99");
+        assert_eq!(format_pct(-1.23456), "-1.23");
+        assert_eq!(format_pct(0.0), "0.00");
+    }
+
+    #[test]
+    fn up_or_down_color_thresholds() {
+        let colors = ChangeColors::default();
+        let (up, down, flat) = (0x10CC84, 0xF6465D, 0xF0CCD4);
+        assert_eq!(up_or_down_color(1.0, &colors), up);
+        assert_eq!(up_or_down_color(250.0, &colors), up);
+        assert_eq!(up_or_down_color(-1.0, &colors), down);
+        assert_eq!(up_or_down_color(0.99, &colors), flat);
+        assert_eq!(up_or_down_color(-0.99, &colors), flat);
+        assert_eq!(up_or_down_color(f64::NAN, &colors), flat);
+    }
+
+    #[test]
+    fn up_or_down_color_uses_configured_values() {
+        let colors = ChangeColors {
+            threshold: 5.,
+            up: 0x00FF00,
+            down: 0xFF0000,
+            flat: 0x888888,
+        };
+        assert_eq!(up_or_down_color(4.9, &colors), 0x888888);
+        assert_eq!(up_or_down_color(5.0, &colors), 0x00FF00);
+        assert_eq!(up_or_down_color(-7.5, &colors), 0xFF0000);
+
+        let zero = ChangeColors {
+            threshold: 0.,
+            ..colors
+        };
+        assert_eq!(up_or_down_color(0.0, &zero), 0x00FF00);
+        assert_eq!(up_or_down_color(-0.01, &zero), 0xFF0000);
+    }
+}